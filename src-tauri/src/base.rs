@@ -2,6 +2,7 @@ use std::collections::HashMap as Map;
 use reqwest::Error as ReqwestError;
 use serde_json::Error as SerdeJsonError;
 use std::io::Error as StdIOError;
+use std::{fmt, path::PathBuf, time::Duration};
 #[derive(Debug, serde::Deserialize)]
 #[serde(deny_unknown_fields, rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct Coin {
@@ -10,8 +11,22 @@ pub struct Coin {
     pub is_selected: bool,
 }
 
+// rates[i][j] converts one unit of currencies[i] into currencies[j], None when unreachable
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ConversionMatrix {
+    pub currencies: Vec<String>,
+    pub rates: Vec<Vec<Option<f32>>>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct FeeAdjustedRate {
+    pub gross: f32,
+    pub net: f32,
+}
+
 pub struct Storage {
-    pub rates: Map<String, f32>
+    pub rates: Map<String, f32>,
+    pub rate_issues: Map<String, RateValidation>,
 }
 
 #[derive(serde::Deserialize)]
@@ -25,11 +40,38 @@ pub struct CryptoRates {
     pub current_price: f32,
 }
 
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub enum RateValidation {
+    Valid,
+    // the rate is kept, but looks suspicious
+    Warning(String),
+    // the rate must not be stored
+    Error(String),
+}
+
 #[derive(Debug)]
 pub enum RateConstructErrors {
     ParseError(serde_json::Error),
+    FileParseError(PathBuf, serde_json::Error),
     FetchError(reqwest::Error),
     IOError(std::io::Error),
+    Timeout(Duration),
+}
+
+impl fmt::Display for RateConstructErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateConstructErrors::ParseError(error) => write!(f, "failed to parse rates: {error}"),
+            RateConstructErrors::FileParseError(path, error) => {
+                write!(f, "failed to parse rates file {}: {error}", path.display())
+            }
+            RateConstructErrors::FetchError(error) => write!(f, "failed to fetch rates: {error}"),
+            RateConstructErrors::IOError(error) => write!(f, "failed to read rates: {error}"),
+            RateConstructErrors::Timeout(timeout) => {
+                write!(f, "fetching rates timed out after {}s", timeout.as_secs_f32())
+            }
+        }
+    }
 }
 
 impl From<ReqwestError> for RateConstructErrors {
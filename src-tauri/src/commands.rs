@@ -1,29 +1,48 @@
 use std::collections::HashMap as Map;
-use crate::base::{Coin, Storage};
+use crate::base::{Coin, ConversionMatrix, FeeAdjustedRate, RateValidation, Storage};
 
 #[tauri::command]
 pub fn get_rates(storage: tauri::State<Storage>) -> Map<String, f32> {
     storage.rates.clone()
 }
 
+#[tauri::command]
+pub fn get_rate_issues(storage: tauri::State<Storage>) -> Map<String, RateValidation> {
+    storage.rate_issues.clone()
+}
+
+// compensated (Kahan) summation, so many small amounts don't drift off the expected total
+fn kahan_sum<I: IntoIterator<Item = f32>>(values: I) -> f32 {
+    let mut sum: f32 = 0.0;
+    let mut compensation: f32 = 0.0;
+    for value in values {
+        let compensated_value = value - compensation;
+        let next_sum = sum + compensated_value;
+        compensation = (next_sum - sum) - compensated_value;
+        sum = next_sum;
+    }
+    sum
+}
+
+fn has_valid_rate(coin: &Coin) -> bool {
+    coin.conversion_rate.is_finite() && coin.conversion_rate > 0.0
+}
+
 #[tauri::command]
 pub async fn calculate_currency_total(selected_currencies: Map<String, Coin>) -> Map<String, f32> {
     let currencies: Vec<(String, Coin)> = selected_currencies.into_iter().collect();
     let mut total: Map<String, f32> = Map::new();
 
-    for (key, currency) in &currencies {
-        total.insert(key.clone(), currency.existing_amount);
-    }
-
     for (key1, currency) in &currencies {
-        for (key2, other_currency) in &currencies {
-            if key1 != key2 {
-                let total_value = (total[key1])
-                    + (other_currency.existing_amount
-                        * (currency.conversion_rate / other_currency.conversion_rate));
-                total.insert(key1.clone(), total_value);
-            }
-        }
+        let converted_amounts = currencies
+            .iter()
+            .filter(|(key2, _)| key1 != key2)
+            .map(|(_, other_currency)| {
+                other_currency.existing_amount
+                    * (currency.conversion_rate / other_currency.conversion_rate)
+            });
+        let total_value = kahan_sum(std::iter::once(currency.existing_amount).chain(converted_amounts));
+        total.insert(key1.clone(), total_value);
     }
 
     return total;
@@ -48,6 +67,87 @@ pub async fn calculate_exchange_rates(selected_currencies: Map<String, Coin>) ->
     return exchange;
 }
 
+// every rate is quoted against a common base whose own rate is 1.0, so a conversion
+// involving the base takes one hop and any other conversion takes two (via the base)
+fn conversion_hops(currency: &Coin, other_currency: &Coin) -> i32 {
+    [currency, other_currency]
+        .iter()
+        .filter(|coin| coin.conversion_rate != 1.0)
+        .count() as i32
+}
+
+#[tauri::command]
+pub async fn calculate_exchange_rates_with_fee(
+    selected_currencies: Map<String, Coin>,
+    fee_bps: u32,
+) -> Map<String, FeeAdjustedRate> {
+    let currencies: Vec<(String, Coin)> = selected_currencies.into_iter().collect();
+    let mut exchange: Map<String, FeeAdjustedRate> = Map::new();
+    let fee_multiplier = 1.0 - fee_bps as f32 / 10_000.0;
+
+    for (key1, currency) in &currencies {
+        for (key2, other_currency) in &currencies {
+            if key1 != key2 && has_valid_rate(currency) && has_valid_rate(other_currency) {
+                let gross = other_currency.conversion_rate / currency.conversion_rate;
+                let net = gross * fee_multiplier.powi(conversion_hops(currency, other_currency));
+                exchange.insert(format!("{key1}/{key2}"), FeeAdjustedRate { gross, net });
+            }
+        }
+    }
+
+    exchange
+}
+
+#[tauri::command]
+pub async fn calculate_conversion_matrix(selected_currencies: Map<String, Coin>) -> ConversionMatrix {
+    let mut currencies: Vec<(String, Coin)> = selected_currencies.into_iter().collect();
+    currencies.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
+
+    let rates: Vec<Vec<Option<f32>>> = currencies
+        .iter()
+        .map(|(key1, currency)| {
+            currencies
+                .iter()
+                .map(|(key2, other_currency)| {
+                    if key1 == key2 {
+                        Some(1.0)
+                    } else if has_valid_rate(currency) && has_valid_rate(other_currency) {
+                        Some(other_currency.conversion_rate / currency.conversion_rate)
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    ConversionMatrix {
+        currencies: currencies.into_iter().map(|(key, _)| key).collect(),
+        rates,
+    }
+}
+
+#[tauri::command]
+pub async fn calculate_currency_shares(selected_currencies: Map<String, Coin>) -> Map<String, f32> {
+    // conversion rates are relative to a common base, so amounts are compared in that base,
+    // coins without a usable rate can't be compared and are left out
+    let values: Map<String, f32> = selected_currencies
+        .into_iter()
+        .filter(|(_, currency)| has_valid_rate(currency))
+        .map(|(key, currency)| (key, currency.existing_amount / currency.conversion_rate))
+        .collect();
+    let total_value = kahan_sum(values.values().copied());
+
+    if total_value == 0.0 || !total_value.is_finite() {
+        return Map::new();
+    }
+
+    values
+        .into_iter()
+        .map(|(key, value)| (key, value / total_value * 100.0))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -85,4 +185,122 @@ mod tests {
         let result = calculate_currency_total(rates).await;
         assert_eq!(result, expected_result);
     }
+
+    #[test]
+    fn test_kahan_sum_does_not_drift() {
+        let amounts = vec![0.1_f32; 10_000];
+        let naive_sum: f32 = amounts.iter().sum();
+        let compensated_sum = kahan_sum(amounts);
+
+        assert!((naive_sum - 1000.0).abs() > 0.01);
+        assert!((compensated_sum - 1000.0).abs() < 0.001);
+    }
+
+    #[tokio::test]
+    async fn test_share_calculation() {
+        let rates: Map<String, Coin> = Map::from([(String::from("USD"), Coin {
+            conversion_rate: 1.0,
+            existing_amount: 1.0,
+            is_selected: true
+        }), (String::from("EUR"), Coin {
+            conversion_rate: 0.5,
+            existing_amount: 1.5,
+            is_selected: true
+        })]);
+
+        let result = calculate_currency_shares(rates).await;
+        assert!((result["USD"] - 25.0).abs() < 0.001);
+        assert!((result["EUR"] - 75.0).abs() < 0.001);
+        assert!((result.values().sum::<f32>() - 100.0).abs() < 0.001);
+    }
+
+    #[tokio::test]
+    async fn test_share_calculation_skips_invalid_rates() {
+        let rates: Map<String, Coin> = Map::from([(String::from("USD"), Coin {
+            conversion_rate: 1.0,
+            existing_amount: 1.0,
+            is_selected: true
+        }), (String::from("EUR"), Coin {
+            conversion_rate: 0.5,
+            existing_amount: 1.5,
+            is_selected: true
+        }), (String::from("XXX"), Coin {
+            conversion_rate: 0.0,
+            existing_amount: 5.0,
+            is_selected: true
+        })]);
+
+        let result = calculate_currency_shares(rates).await;
+        assert!(!result.contains_key("XXX"));
+        assert!((result["USD"] - 25.0).abs() < 0.001);
+        assert!((result["EUR"] - 75.0).abs() < 0.001);
+    }
+
+    #[tokio::test]
+    async fn test_share_calculation_with_zero_total() {
+        let rates: Map<String, Coin> = Map::from([(String::from("USD"), Coin {
+            conversion_rate: 1.0,
+            existing_amount: 0.0,
+            is_selected: true
+        })]);
+
+        let result = calculate_currency_shares(rates).await;
+        assert!(result.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_conversion_matrix_calculation() {
+        let rates: Map<String, Coin> = Map::from([(String::from("USD"), Coin {
+            conversion_rate: 1.0,
+            existing_amount: 0.0,
+            is_selected: true
+        }), (String::from("EUR"), Coin {
+            conversion_rate: 0.5,
+            existing_amount: 0.0,
+            is_selected: true
+        }), (String::from("INR"), Coin {
+            conversion_rate: 80.0,
+            existing_amount: 0.0,
+            is_selected: true
+        }), (String::from("XXX"), Coin {
+            conversion_rate: 0.0,
+            existing_amount: 0.0,
+            is_selected: true
+        })]);
+
+        let result = calculate_conversion_matrix(rates).await;
+        assert_eq!(result.currencies, vec!["EUR", "INR", "USD", "XXX"]);
+        for index in 0..result.currencies.len() {
+            assert_eq!(result.rates[index][index], Some(1.0));
+        }
+        // EUR -> INR goes through the common base of both rates
+        assert_eq!(result.rates[0][1], Some(160.0));
+        assert_eq!(result.rates[1][0], Some(0.00625));
+        assert_eq!(result.rates[2][3], None);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_rate_with_fee_calculation() {
+        let rates: Map<String, Coin> = Map::from([(String::from("USD"), Coin {
+            conversion_rate: 1.0,
+            existing_amount: 0.0,
+            is_selected: true
+        }), (String::from("EUR"), Coin {
+            conversion_rate: 0.5,
+            existing_amount: 0.0,
+            is_selected: true
+        }), (String::from("INR"), Coin {
+            conversion_rate: 80.0,
+            existing_amount: 0.0,
+            is_selected: true
+        })]);
+
+        let result = calculate_exchange_rates_with_fee(rates, 100).await;
+        // USD is the base, so USD -> EUR is a single hop
+        assert_eq!(result["USD/EUR"].gross, 0.5);
+        assert!((result["USD/EUR"].net - 0.495).abs() < 0.0001);
+        // EUR -> INR goes through the base, paying the fee on both hops
+        assert_eq!(result["EUR/INR"].gross, 160.0);
+        assert!((result["EUR/INR"].net - 156.816).abs() < 0.001);
+    }
 }
\ No newline at end of file
@@ -6,23 +6,29 @@ pub mod commands;
 pub mod rates;
 
 use base::Storage;
-use commands::{calculate_currency_total, calculate_exchange_rates, get_rates};
+use commands::{
+    calculate_conversion_matrix, calculate_currency_shares, calculate_currency_total,
+    calculate_exchange_rates, calculate_exchange_rates_with_fee, get_rate_issues, get_rates,
+};
 use rates::get_parsed_rates;
 
-use std::collections::HashMap as Map;
 use tokio;
 
 #[tokio::main]
 async fn main() {
-    let rates: Map<String, f32> = get_parsed_rates().await;
+    let (rates, rate_issues) = get_parsed_rates().await;
 
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
+            calculate_conversion_matrix,
+            calculate_currency_shares,
             calculate_currency_total,
             calculate_exchange_rates,
+            calculate_exchange_rates_with_fee,
+            get_rate_issues,
             get_rates
         ])
-        .manage(Storage { rates })
+        .manage(Storage { rates, rate_issues })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -1,14 +1,21 @@
 use directories::ProjectDirs;
 use reqwest;
-use std::io::{Read, Write};
+use std::future::Future;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{collections::HashMap as Map, fs::create_dir_all, fs::File};
 
-use crate::base::{CryptoRates, FiatRates, RateConstructErrors};
+use crate::base::{CryptoRates, FiatRates, RateConstructErrors, RateValidation};
 
 const FIAT_RATES_FETCH_URL: &str =
     "https://raw.githubusercontent.com/ARK-Builders/ark-exchange-rates/main/fiat-rates.json";
 const CRYPTO_RATES_FETCH_URL: &str =
     "https://raw.githubusercontent.com/ARK-Builders/ark-exchange-rates/main/crypto-rates.json";
+// a fetched rate moving more than this factor away from the stored one is flagged with a warning,
+// the flagged rate is still used and cached so that a genuine move is only reported once
+const MAX_RATE_DEVIATION_FACTOR: f32 = 10.0;
+const DEFAULT_RATES_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
 
 async fn fetch_rates() -> Result<(String, String), reqwest::Error> {
     let fiat_rates = reqwest::get(FIAT_RATES_FETCH_URL).await?.text().await?;
@@ -16,22 +23,68 @@ async fn fetch_rates() -> Result<(String, String), reqwest::Error> {
     Ok((fiat_rates, crypto_rates))
 }
 
+fn normalize_currency_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+// when several codes normalize to the same one, a code already in canonical form wins,
+// otherwise the lexicographically smallest original code does, so the result never
+// depends on the map's iteration order
+fn normalize_currency_codes(rates: Map<String, f32>) -> Map<String, f32> {
+    let mut normalized_rates: Map<String, (String, f32)> = Map::new();
+    for (code, rate) in rates {
+        let normalized_code = normalize_currency_code(&code);
+        let keep_existing = match normalized_rates.get(&normalized_code) {
+            Some((existing_code, _)) => {
+                *existing_code == normalized_code || (code != normalized_code && *existing_code < code)
+            }
+            None => false,
+        };
+        if !keep_existing {
+            normalized_rates.insert(normalized_code, (code, rate));
+        }
+    }
+    normalized_rates
+        .into_iter()
+        .map(|(code, (_, rate))| (code, rate))
+        .collect()
+}
+
 fn parse_fiat_rates(unparsed_rates: String) -> Result<Map<String, f32>, serde_json::Error> {
     let fiat_rates = serde_json::from_str::<FiatRates>(&unparsed_rates)?;
-    Ok(fiat_rates.rates)
+    Ok(normalize_currency_codes(fiat_rates.rates))
 }
 
 fn parse_crypto_rates(unparsed_rates: String) -> Result<Map<String, f32>, serde_json::Error> {
     let crypto_rates = serde_json::from_str::<Vec<CryptoRates>>(&unparsed_rates)?;
     let mut rates: Map<String, f32> = Map::new();
     for crypto in crypto_rates {
-        rates.insert(crypto.symbol.to_uppercase(), 1.0 / crypto.current_price);
+        rates.insert(normalize_currency_code(&crypto.symbol), 1.0 / crypto.current_price);
     }
     Ok(rates)
 }
 
-pub async fn construct_rates() -> Result<Map<String, f32>, RateConstructErrors> {
-    let (unparsed_fiat_rates, unparsed_crypto_rates) = fetch_rates().await?;
+// the future is dropped once the timeout elapses, which cancels any request still in flight
+async fn with_timeout<T, E, F>(future: F, timeout: Duration) -> Result<T, RateConstructErrors>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<RateConstructErrors>,
+{
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(RateConstructErrors::Timeout(timeout)),
+    }
+}
+
+pub async fn construct_rates(timeout: Duration) -> Result<Map<String, f32>, RateConstructErrors> {
+    construct_rates_from(fetch_rates(), timeout).await
+}
+
+async fn construct_rates_from<F>(fetch: F, timeout: Duration) -> Result<Map<String, f32>, RateConstructErrors>
+where
+    F: Future<Output = Result<(String, String), reqwest::Error>>,
+{
+    let (unparsed_fiat_rates, unparsed_crypto_rates) = with_timeout(fetch, timeout).await?;
 
     let fiat_rates = parse_fiat_rates(unparsed_fiat_rates)?;
     let crypto_rates = parse_crypto_rates(unparsed_crypto_rates)?;
@@ -43,24 +96,81 @@ pub async fn construct_rates() -> Result<Map<String, f32>, RateConstructErrors>
     Ok(rates)
 }
 
-fn create_or_use_rates_file(file_name: &str, empty: bool) -> File {
+fn validate_rate(rate: f32, previous_rate: Option<f32>, max_deviation_factor: f32) -> RateValidation {
+    if !rate.is_finite() || rate <= 0.0 {
+        return RateValidation::Error(format!("rate {rate} is not a positive number"));
+    }
+    match previous_rate {
+        Some(previous) if previous.is_finite() && previous > 0.0 => {
+            let deviation = if rate > previous { rate / previous } else { previous / rate };
+            if deviation > max_deviation_factor {
+                RateValidation::Warning(format!(
+                    "rate {rate} deviates from the stored rate {previous} by more than {max_deviation_factor}x"
+                ))
+            } else {
+                RateValidation::Valid
+            }
+        }
+        _ => RateValidation::Valid,
+    }
+}
+
+// rates with validation errors fall back to the previously stored rate, or are dropped if there is no valid one,
+// while rates with warnings are kept; every non-valid rate is reported alongside the sanitized rates
+fn sanitize_rates(
+    rates: Map<String, f32>,
+    previous_rates: &Map<String, f32>,
+    max_deviation_factor: f32,
+) -> (Map<String, f32>, Map<String, RateValidation>) {
+    let mut sanitized_rates: Map<String, f32> = Map::new();
+    let mut issues: Map<String, RateValidation> = Map::new();
+    for (code, rate) in rates {
+        let previous_rate = previous_rates.get(&code).copied();
+        match validate_rate(rate, previous_rate, max_deviation_factor) {
+            RateValidation::Valid => {
+                sanitized_rates.insert(code, rate);
+            }
+            warning @ RateValidation::Warning(_) => {
+                sanitized_rates.insert(code.clone(), rate);
+                issues.insert(code, warning);
+            }
+            error @ RateValidation::Error(_) => {
+                // caches written by older versions may themselves hold invalid rates
+                let valid_previous_rate = previous_rate.filter(|previous| {
+                    validate_rate(*previous, None, max_deviation_factor) == RateValidation::Valid
+                });
+                if let Some(previous) = valid_previous_rate {
+                    sanitized_rates.insert(code.clone(), previous);
+                }
+                issues.insert(code, error);
+            }
+        }
+    }
+    (sanitized_rates, issues)
+}
+
+fn rates_file_path(file_name: &str) -> PathBuf {
     if let Some(proj_dirs) = ProjectDirs::from("com", "ark-builders", "ark-rate-desktop") {
-        let path = proj_dirs.cache_dir();
-        // must panic if failed to create/use the rates file
-        let _ = create_dir_all(&path)
-            .expect("Failed to create the missing directories for storing the rates");
-        let path = path.join(file_name);
-        File::options()
-            .read(true)
-            .write(true)
-            .truncate(empty)
-            .open(&path)
-            .unwrap_or_else(|_| File::create(&path).unwrap())
+        proj_dirs.cache_dir().join(file_name)
     } else {
         panic!("No valid home directory path could be retrieved from the operating system.");
     }
 }
 
+fn create_or_use_rates_file(path: &Path, empty: bool) -> File {
+    if let Some(parent) = path.parent() {
+        // must panic if failed to create/use the rates file
+        create_dir_all(parent)
+            .expect("Failed to create the missing directories for storing the rates");
+    }
+    File::options()
+        .read(true)
+        .write(true)
+        .truncate(empty)
+        .open(path)
+        .unwrap_or_else(|_| File::create(path).unwrap())
+}
+
 fn write_rates_to_file(rates: &Map<String, f32>, mut file: &File) {
     let bytes = serde_json::to_string(rates).expect("Must not fail to serialize the json into a string");
     file.set_len(0).unwrap();
@@ -68,31 +178,72 @@ fn write_rates_to_file(rates: &Map<String, f32>, mut file: &File) {
     file.flush().unwrap();
 }
 
-fn read_rates_from_file(mut file: &File) -> Result<Map<String, f32>, RateConstructErrors> {
+fn read_unnormalized_rates_from_file(path: &Path) -> Result<Map<String, f32>, RateConstructErrors> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        // nothing has been stored yet, reading must not create the missing directories
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Map::new()),
+        Err(error) => return Err(error.into()),
+    };
     let mut unparsed_rates = String::new();
     file.read_to_string(&mut unparsed_rates)?;
-    let parsed_rates = serde_json::from_str::<Map<String, f32>>(&unparsed_rates)?;
+    let parsed_rates = serde_json::from_str::<Map<String, f32>>(&unparsed_rates)
+        .map_err(|error| RateConstructErrors::FileParseError(path.to_path_buf(), error))?;
     Ok(parsed_rates)
 }
 
-pub async fn get_parsed_rates() -> Map<String, f32> {
-    let parsed_rates: Map<String, f32>;
-    let result = construct_rates().await;
+fn read_rates_from_file(path: &Path) -> Result<Map<String, f32>, RateConstructErrors> {
+    // rates cached by older versions may hold codes in any case
+    Ok(normalize_currency_codes(read_unnormalized_rates_from_file(path)?))
+}
+
+fn try_write_rates_to_file(rates: &Map<String, f32>, path: &Path) -> Result<(), RateConstructErrors> {
+    let bytes = serde_json::to_string(rates)?;
+    let mut file = File::create(path)?;
+    file.write_all(bytes.as_bytes())?;
+    file.flush()?;
+    Ok(())
+}
+
+// rewrites the cache only when normalizing changed a code cached by an older version,
+// the rates are already in memory so failing to rewrite (e.g. on read-only media) is not fatal
+fn load_cached_rates(path: &Path) -> Result<Map<String, f32>, RateConstructErrors> {
+    let cached_rates = read_unnormalized_rates_from_file(path)?;
+    let normalized_rates = normalize_currency_codes(cached_rates.clone());
+    let codes_changed = cached_rates.len() != normalized_rates.len()
+        || cached_rates.keys().any(|code| !normalized_rates.contains_key(code));
+    if codes_changed {
+        let _ = try_write_rates_to_file(&normalized_rates, path);
+    }
+    Ok(normalized_rates)
+}
+
+fn store_fetched_rates(
+    rates: Map<String, f32>,
+    path: &Path,
+) -> (Map<String, f32>, Map<String, RateValidation>) {
+    let previous_rates = read_rates_from_file(path).unwrap_or_default();
+    let (sanitized_rates, issues) = sanitize_rates(rates, &previous_rates, MAX_RATE_DEVIATION_FACTOR);
+    let rates_file = create_or_use_rates_file(path, true);
+    write_rates_to_file(&sanitized_rates, &rates_file);
+    (sanitized_rates, issues)
+}
+
+pub async fn get_parsed_rates() -> (Map<String, f32>, Map<String, RateValidation>) {
+    let result = construct_rates(DEFAULT_RATES_FETCH_TIMEOUT).await;
+    let rates_path = rates_file_path("rates");
 
     match result {
-        Ok(rates) => {
-            parsed_rates = rates;
-            let rates_file = create_or_use_rates_file("rates", true);
-            write_rates_to_file(&parsed_rates, &rates_file);
-        }
-        Err(_) => {
-            let rates_file = create_or_use_rates_file("rates", false);
-            parsed_rates = read_rates_from_file(&rates_file)
-                .expect("Failed to retrieve rates which is required")
+        Ok(rates) => store_fetched_rates(rates, &rates_path),
+        Err(fetch_error) => {
+            let parsed_rates = load_cached_rates(&rates_path)
+                .unwrap_or_else(|error| panic!("Failed to retrieve rates which is required: {error}"));
+            if parsed_rates.is_empty() {
+                panic!("Failed to retrieve rates which is required: {fetch_error}");
+            }
+            (parsed_rates, Map::new())
         }
     }
-
-    parsed_rates
 }
 
 #[cfg(test)]
@@ -135,12 +286,31 @@ mod tests {
         let rates_string = "{ \"USD\": 1.0, \"INR\": 0.01 }";
         let rates = serde_json::from_str::<Map<String, f32>>(rates_string).unwrap();
         add_string_to_file(rates_string, &mut file);
-        file.rewind().unwrap();
 
-        let parsed_rates = read_rates_from_file(&file).unwrap();
+        let parsed_rates = read_rates_from_file(Path::new("read-rates")).unwrap();
         assert_eq!(rates, parsed_rates);
     }
 
+    #[test]
+    fn test_read_malformed_rates_from_file() {
+        let mut file = create_or_use_file("malformed-rates", true);
+        add_string_to_file("{ \"USD\": 1.0,\n \"INR\": }", &mut file);
+
+        let error = read_rates_from_file(Path::new("malformed-rates")).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("malformed-rates"));
+        assert!(message.contains("line 2"));
+    }
+
+    #[test]
+    fn test_read_rates_from_missing_directory() {
+        let path = Path::new("missing-rates-dir").join("rates");
+
+        let parsed_rates = read_rates_from_file(&path).unwrap();
+        assert!(parsed_rates.is_empty());
+        assert!(!Path::new("missing-rates-dir").exists());
+    }
+
     #[test]
     fn test_parse_fiat_rates() {
         let unparsed_rates = "{ \"rates\": {\"USD\": 1.0, \"INR\": 0.01} }";
@@ -150,6 +320,70 @@ mod tests {
         assert_eq!(parsed_rates, result);
     }
 
+    #[test]
+    fn test_parse_fiat_rates_normalizes_codes() {
+        let unparsed_rates = "{ \"rates\": {\" usd\": 1.0, \"Inr\": 0.01} }";
+        let parsed_rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 0.01)]);
+
+        let result = parse_fiat_rates(String::from(unparsed_rates)).unwrap();
+        assert_eq!(parsed_rates, result);
+    }
+
+    #[test]
+    fn test_read_rates_from_file_normalizes_codes() {
+        let mut file = create_or_use_file("mixed-case-rates", true);
+        add_string_to_file("{ \"usd\": 1.0, \"Inr \": 0.01 }", &mut file);
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 0.01)]);
+
+        let parsed_rates = read_rates_from_file(Path::new("mixed-case-rates")).unwrap();
+        assert_eq!(rates, parsed_rates);
+    }
+
+    #[test]
+    fn test_normalize_currency_codes_prefers_canonical_code() {
+        let rates: Map<String, f32> = Map::from([
+            (String::from("usd"), 2.0),
+            (String::from("USD"), 1.0),
+            (String::from("Usd "), 3.0),
+            (String::from("inr"), 0.02),
+            (String::from("Inr"), 0.01),
+        ]);
+        let expected_result: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 0.01)]);
+
+        let result = normalize_currency_codes(rates);
+        assert_eq!(expected_result, result);
+    }
+
+    #[test]
+    fn test_load_cached_rates_rewrites_normalized_codes() {
+        let mut file = create_or_use_file("legacy-rates", true);
+        add_string_to_file("{ \"usd\": 1.0, \"Inr\": 0.01 }", &mut file);
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 0.01)]);
+
+        let loaded_rates = load_cached_rates(Path::new("legacy-rates")).unwrap();
+        assert_eq!(rates, loaded_rates);
+
+        let mut file_data = String::new();
+        File::open("legacy-rates").unwrap().read_to_string(&mut file_data).unwrap();
+        let stored_rates = serde_json::from_str::<Map<String, f32>>(&file_data).unwrap();
+        assert_eq!(rates, stored_rates);
+    }
+
+    #[test]
+    fn test_load_cached_rates_leaves_normalized_cache_untouched() {
+        let rates_string = "{ \"USD\": 1.0, \"INR\": 0.01 }";
+        let mut file = create_or_use_file("normalized-rates", true);
+        add_string_to_file(rates_string, &mut file);
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 0.01)]);
+
+        let loaded_rates = load_cached_rates(Path::new("normalized-rates")).unwrap();
+        assert_eq!(rates, loaded_rates);
+
+        let mut file_data = String::new();
+        File::open("normalized-rates").unwrap().read_to_string(&mut file_data).unwrap();
+        assert_eq!(rates_string, file_data);
+    }
+
     #[test]
     fn test_parse_crypto_rates() {
         let unparsed_rates = "[ { \"symbol\": \"USD\", \"current_price\": 1 }, {\"symbol\": \"INR\", \"current_price\": 100} ]";
@@ -158,4 +392,104 @@ mod tests {
         let result = parse_crypto_rates(String::from(unparsed_rates)).unwrap();
         assert_eq!(parsed_rates, result);    
     }
+
+    #[tokio::test]
+    async fn test_fetch_times_out() {
+        let slow_fetch = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<(String, String), reqwest::Error>((String::new(), String::new()))
+        };
+        let started_at = std::time::Instant::now();
+
+        let result = with_timeout(slow_fetch, Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(RateConstructErrors::Timeout(_))));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_construct_rates_times_out() {
+        let slow_fetch = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<(String, String), reqwest::Error>((String::new(), String::new()))
+        };
+        let started_at = std::time::Instant::now();
+
+        let result = construct_rates_from(slow_fetch, Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(RateConstructErrors::Timeout(_))));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_construct_rates_within_timeout() {
+        let fetch = async {
+            Ok::<(String, String), reqwest::Error>((
+                String::from("{ \"rates\": {\"USD\": 1.0} }"),
+                String::from("[ {\"symbol\": \"btc\", \"current_price\": 2} ]"),
+            ))
+        };
+        let expected_rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("BTC"), 0.5)]);
+
+        let rates = construct_rates_from(fetch, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(expected_rates, rates);
+    }
+
+    #[test]
+    fn test_sanitize_rates_accepts_normal_rate() {
+        let previous_rates: Map<String, f32> = Map::from([(String::from("INR"), 0.01)]);
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 0.012)]);
+
+        let (result, issues) = sanitize_rates(rates.clone(), &previous_rates, MAX_RATE_DEVIATION_FACTOR);
+        assert_eq!(rates, result);
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn test_sanitize_rates_categorizes_warnings_and_errors() {
+        let previous_rates: Map<String, f32> = Map::from([(String::from("INR"), 0.01), (String::from("EUR"), 0.9)]);
+        let rates: Map<String, f32> = Map::from([(String::from("INR"), 5.0), (String::from("EUR"), 0.0)]);
+        let expected_result: Map<String, f32> = Map::from([(String::from("INR"), 5.0), (String::from("EUR"), 0.9)]);
+
+        let (result, issues) = sanitize_rates(rates, &previous_rates, MAX_RATE_DEVIATION_FACTOR);
+        assert_eq!(expected_result, result);
+        assert!(matches!(issues["INR"], RateValidation::Warning(_)));
+        assert!(matches!(issues["EUR"], RateValidation::Error(_)));
+    }
+
+    #[test]
+    fn test_store_fetched_rates_converges_after_a_move() {
+        let mut file = create_or_use_file("moved-rates", true);
+        add_string_to_file("{ \"USD\": 1.0, \"INR\": 0.01 }", &mut file);
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("INR"), 5.0)]);
+
+        let (first_rates, first_issues) = store_fetched_rates(rates.clone(), Path::new("moved-rates"));
+        assert_eq!(rates, first_rates);
+        assert!(matches!(first_issues["INR"], RateValidation::Warning(_)));
+        assert_eq!(rates, read_rates_from_file(Path::new("moved-rates")).unwrap());
+
+        let (second_rates, second_issues) = store_fetched_rates(rates.clone(), Path::new("moved-rates"));
+        assert_eq!(rates, second_rates);
+        assert!(second_issues.is_empty());
+        assert_eq!(rates, read_rates_from_file(Path::new("moved-rates")).unwrap());
+    }
+
+    #[test]
+    fn test_sanitize_rates_drops_invalid_rate_without_previous() {
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("BTC"), f32::INFINITY)]);
+        let expected_result: Map<String, f32> = Map::from([(String::from("USD"), 1.0)]);
+
+        let (result, issues) = sanitize_rates(rates, &Map::new(), MAX_RATE_DEVIATION_FACTOR);
+        assert_eq!(expected_result, result);
+        assert!(matches!(issues["BTC"], RateValidation::Error(_)));
+    }
+
+    #[test]
+    fn test_sanitize_rates_drops_invalid_rate_with_invalid_previous() {
+        let previous_rates: Map<String, f32> = Map::from([(String::from("EUR"), 0.0)]);
+        let rates: Map<String, f32> = Map::from([(String::from("USD"), 1.0), (String::from("EUR"), -1.0)]);
+        let expected_result: Map<String, f32> = Map::from([(String::from("USD"), 1.0)]);
+
+        let (result, issues) = sanitize_rates(rates, &previous_rates, MAX_RATE_DEVIATION_FACTOR);
+        assert_eq!(expected_result, result);
+        assert!(matches!(issues["EUR"], RateValidation::Error(_)));
+    }
 }